@@ -0,0 +1,141 @@
+//! Extractor-based handler registration.
+//!
+//! Every handler used to have to be a full `actix` `Actor` implementing
+//! `Handler<M>`, which is heavy for stateless request/response logic.
+//! Borrowing the `FromRequest`/`State` pattern from axum and jsonrpc-v2,
+//! [`add_fn_handler`] registers a plain closure `Fn(M, State<S>) -> Result<M::Response, Error>`
+//! as a route by generating a tiny actor around it internally; the existing
+//! `Router`/`Recipient` plumbing is unchanged.
+//!
+//! `State<S>` itself is just a cheap-to-clone `Arc<S>`. The free
+//! [`add_fn_handler`] in this module takes one directly, for callers who
+//! already have their own `S` to hand; `Service::build(...).with_state` /
+//! `.add_fn_handler` (see [`crate::service`]) instead stores the `S` on the
+//! `Service`, so every `add_fn_handler` registered afterwards for that `S`
+//! — across any number of message types `M` — shares the same `Arc<S>`.
+use actix::{Actor, Context, Handler};
+use failure::Error;
+use futures::{future, Future};
+
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::router::{add_route, SoarMessage, SoarResponse};
+
+/// Shared service state handed to a route registered with
+/// [`add_fn_handler`]. Cheap to clone: it's a reference-counted pointer to
+/// the `S` given at registration.
+pub struct State<S>(Arc<S>);
+
+impl<S> State<S> {
+    pub fn new(state: S) -> Self {
+        State(Arc::new(state))
+    }
+}
+
+impl<S> Clone for State<S> {
+    fn clone(&self) -> Self {
+        State(self.0.clone())
+    }
+}
+
+impl<S> Deref for State<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.0
+    }
+}
+
+/// The actor generated by [`add_fn_handler`] to wrap a closure as a
+/// `Handler<M>`, so it can be registered via the usual `add_route`.
+struct FnHandler<M, S, F>
+    where M: SoarMessage,
+          S: 'static,
+          F: Fn(M, State<S>) -> Result<M::Response, Error>,
+{
+    f: F,
+    state: State<S>,
+    _msg: PhantomData<M>,
+}
+
+impl<M, S, F> Actor for FnHandler<M, S, F>
+    where M: SoarMessage,
+          S: 'static,
+          F: 'static + Fn(M, State<S>) -> Result<M::Response, Error>,
+{
+    type Context = Context<Self>;
+}
+
+impl<M, S, F> Handler<M> for FnHandler<M, S, F>
+    where M: SoarMessage,
+          S: 'static,
+          F: 'static + Fn(M, State<S>) -> Result<M::Response, Error>,
+{
+    type Result = SoarResponse<M>;
+
+    fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
+        let res = (self.f)(msg, self.state.clone());
+        SoarResponse(Box::new(future::result(res)))
+    }
+}
+
+/// Register `f` as the handler for `M`, without hand-writing an actor.
+/// `state` is handed to every invocation via the `State<S>` extractor;
+/// pass `State::new(())` if the closure doesn't need any. Consistent with
+/// `add_route`/`add_route_fut`/`del_route`, this is a free function rather
+/// than a `Service`-only method; `Service::build(...).add_fn_handler(...)`
+/// is the version backed by state shared across a `Service`'s
+/// registrations (see [`add_fn_handler_from_service_state`]).
+pub fn add_fn_handler<M, S, F>(f: F, state: State<S>)
+    where M: SoarMessage,
+          S: 'static,
+          F: 'static + Fn(M, State<S>) -> Result<M::Response, Error>,
+{
+    let actor = FnHandler { f, state, _msg: PhantomData };
+    add_route::<M, _>(actor.start());
+}
+
+/// Register `f` as the handler for `M` once `state_fut` resolves, the same
+/// way [`crate::router::add_route_fut`] queues messages onto a route
+/// that isn't ready yet: used by `ServiceBuilder::add_fn_handler` to pull
+/// `State<S>` from the state registered via `ServiceBuilder::with_state`,
+/// rather than requiring every call site to construct its own `State<S>`.
+pub(crate) fn add_fn_handler_from_service_state<M, S, F>(
+    f: F,
+    state_fut: impl Future<Item=Arc<S>, Error=()> + 'static + Send,
+)
+    where M: SoarMessage,
+          S: 'static + Send + Sync,
+          F: 'static + Send + Fn(M, State<S>) -> Result<M::Response, Error>,
+{
+    let fut = state_fut.map(move |state| {
+        let actor = FnHandler { f, state: State(state), _msg: PhantomData };
+        actor.start().recipient()
+    });
+    crate::router::add_route_fut::<M, _, _>(fut);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestServer;
+
+    use super::*;
+    use crate::router::send;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn test_add_fn_handler() {
+        init_logger();
+        let server = TestServer::new(|_| {});
+        let res = server.execute(futures::future::lazy(|| {
+            add_fn_handler::<TestMessage, u8, _>(
+                |msg, state: State<u8>| Ok(TestResponse(msg.0 + *state)),
+                State::new(1),
+            );
+            send(TestMessage(7))
+        })).unwrap();
+        assert_eq!(res.0, 8);
+    }
+}
@@ -7,42 +7,84 @@ use log::*;
 use url::Url;
 
 use std::marker::PhantomData;
+use std::sync::Arc;
 
+use crate::http::codec::{Bincode, Codec};
+use crate::http::pool::{HttpClientOptions, ResilientClient};
 use crate::service::*;
 
 impl<M: SoarMessage> From<Url> for HttpHandler<M> {
     fn from(other: Url) -> Self {
-        HttpHandler(other, PhantomData)
+        HttpHandler(other, Bincode::default(), None, PhantomData)
     }
 }
 
+/// A lightweight service-discovery hook: register `M` against whatever
+/// address `url_fut` eventually resolves to (e.g. the result of a registry
+/// lookup), queuing any messages sent in the meantime the same way
+/// `add_route_fut` does. Lets a remote service come and go without callers
+/// restarting to pick up its new address.
+pub fn resolve_remote<M, F>(url_fut: F)
+    where M: SoarMessage,
+          F: 'static + Future<Item=Url, Error=()> + Send,
+{
+    let handler_fut = url_fut.map(|url| crate::service::into_recipient(HttpHandler::<M>::from(url)));
+    crate::router::add_route_fut::<M, _, _>(handler_fut);
+}
+
 /// The `HttpHandler` wraps a `Url` and behaves as a handler for the generic
 /// type `M`. This can be registered as a usual `RequestHandler<M>`, but the
-/// fact that the actual handler is remote is opaque to the application. 
-pub struct HttpHandler<M>(pub Url, PhantomData<M>);
+/// fact that the actual handler is remote is opaque to the application.
+///
+/// The wire format defaults to `Bincode`; use [`HttpHandler::with_codec`] to
+/// pick `Json` or `MessagePack` instead. By default each call opens a fresh
+/// connection with no retry; use [`HttpHandler::with_options`] (what
+/// `Service::build().add_http_handler_with_options` wires up) to route
+/// through a pooled, retrying, circuit-breaking [`ResilientClient`] instead.
+pub struct HttpHandler<M, C: Codec = Bincode>(pub Url, C, Option<Arc<ResilientClient>>, PhantomData<M>);
+
+impl<M: SoarMessage, C: Codec> HttpHandler<M, C> {
+    /// Build a handler that frames requests/responses with `codec` instead
+    /// of the default `Bincode`.
+    pub fn with_codec(url: Url, codec: C) -> Self {
+        HttpHandler(url, codec, None, PhantomData)
+    }
 
-impl<M: SoarMessage> RequestHandler<M> for HttpHandler<M> {
+    /// Build a handler backed by a pooled, resilient client configured by
+    /// `options`: bounded connections per host, retry-with-backoff on
+    /// transport errors, and a circuit breaker per target.
+    pub fn with_options(url: Url, codec: C, options: HttpClientOptions) -> Self {
+        HttpHandler(url, codec, Some(Arc::new(ResilientClient::new(options))), PhantomData)
+    }
+}
+
+impl<M: SoarMessage, C: Codec> RequestHandler<M> for HttpHandler<M, C> {
     fn handle_request(&mut self, msg: M, _: Addr<Service>) -> RespFuture<M> {
         let url = self.0.clone();
         let path = url.path().to_string();
-        let msg = bincode::serialize(&msg).map_err(Error::from);
+        let content_type = self.1.content_type();
+        let msg = self.1.serialize(&msg);
         trace!("Channel making request to Actor running at {} on path {}", url.host_str().unwrap_or(""), path);
+        let codec = self.1.clone();
+        let client = self.2.clone();
+
         let fut = future::result(msg).and_then(move |msg| {
-            ClientRequest::post(url)
-                .body(msg)
-                .unwrap()
-                .send()
-                .map_err(Error::from)
-                .and_then(|resp| {
-                    // Deserialize the JSON and map the error
-                    resp.body().map_err(Error::from)
-                })
-                .and_then(|body| {
-                    future::result(bincode::deserialize(&body))
+            let body: Box<Future<Item=Vec<u8>, Error=Error>> = match client {
+                Some(client) => client.post(url, content_type, msg),
+                None => Box::new(
+                    ClientRequest::post(url)
+                        .header("Content-Type", content_type)
+                        .header("Accept", content_type)
+                        .body(msg)
+                        .unwrap()
+                        .send()
                         .map_err(Error::from)
-                })
+                        .and_then(|resp| resp.body().map_err(Error::from).map(|b| b.to_vec()))
+                ),
+            };
+            body.and_then(move |body| future::result(codec.deserialize(&body)))
         });
-        
+
         Box::new(fut)
     }
 }
@@ -50,6 +92,7 @@ impl<M: SoarMessage> RequestHandler<M> for HttpHandler<M> {
 #[cfg(test)]
 mod tests {
     use actix_web::test::TestServer;
+    use futures::sync::oneshot;
 
     use super::*;
     use crate::test_helpers::*;
@@ -75,4 +118,72 @@ mod tests {
         })).unwrap();
         assert_eq!(res.0, 138);
     }
+
+    #[test]
+    fn test_http_channel_json_codec() {
+        init_logger();
+        let mut server = TestServer::new(|app| {
+            app.resource("/test", |r| r.f(|_| {
+                trace!("Received request! Responding with answer");
+                actix_web::HttpResponse::Ok().json(TestResponse(138))
+            }));
+        });
+
+        let url = Url::parse(&server.url("/test")).unwrap();
+        let res = server.execute(futures::future::lazy(|| {
+            let addr = Service::build("http_channel_test_client_json")
+                                        .add_handler(HttpHandler::with_codec(url.clone(), crate::http::codec::Json))
+                                        .address();
+            addr.send(TestMessage(138))
+        })).unwrap();
+        assert_eq!(res.0, 138);
+    }
+
+    #[test]
+    fn test_http_channel_pooled() {
+        init_logger();
+        let mut server = TestServer::new(|app| {
+            app.resource("/test", |r| r.f(|_| {
+                trace!("Received request! Responding with answer");
+                let msg = bincode::serialize(&TestResponse(138)).unwrap();
+                actix_web::HttpResponse::Ok().body(msg)
+            }));
+        });
+
+        let url = Url::parse(&server.url("/test")).unwrap();
+        let res = server.execute(futures::future::lazy(|| {
+            let addr = Service::build("http_channel_test_client_pooled")
+                                        .add_handler(HttpHandler::with_options(url.clone(), Bincode::default(), HttpClientOptions::default()))
+                                        .address();
+            addr.send(TestMessage(138))
+        })).unwrap();
+        assert_eq!(res.0, 138);
+    }
+
+    #[test]
+    fn test_resolve_remote_queues_during_pending_window_then_resolves() {
+        init_logger();
+        let mut server = TestServer::new(|app| {
+            app.resource("/test", |r| r.f(|_| {
+                trace!("Received request! Responding with answer");
+                let msg = bincode::serialize(&TestResponse(138)).unwrap();
+                actix_web::HttpResponse::Ok().body(msg)
+            }));
+        });
+
+        let url = Url::parse(&server.url("/test")).unwrap();
+        let res = server.execute(futures::future::lazy(|| {
+            let (tx, rx) = oneshot::channel::<Url>();
+            resolve_remote::<TestMessage, _>(rx.map_err(|_| ()));
+
+            // Sent while the remote address is still unresolved: should
+            // queue onto `url_fut` rather than failing with RouterError.
+            let pending = crate::router::send(TestMessage(138));
+
+            tx.send(url).ok();
+
+            pending
+        })).unwrap();
+        assert_eq!(res.0, 138);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,118 @@
+//! Pluggable wire codecs for [`HttpHandler`](super::client::HttpHandler).
+//!
+//! `HttpHandler` used to hardcode bincode; `Codec` lets callers trade
+//! debuggability (JSON) against compactness (bincode/msgpack) per route,
+//! and sets the `Content-Type`/`Accept` headers so the receiving `Service`
+//! can content-negotiate.
+use failure::Error;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Serializes/deserializes the body of a remote call, and names the
+/// `Content-Type` it produces so the receiving end can pick a matching
+/// decoder.
+pub trait Codec: Default + Clone {
+    fn serialize<T: Serialize>(&self, t: &T) -> Result<Vec<u8>, Error>;
+    fn deserialize<T: DeserializeOwned>(&self, b: &[u8]) -> Result<T, Error>;
+    fn content_type(&self) -> &'static str;
+}
+
+/// The original wire format: compact, but opaque to anything outside soar.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn serialize<T: Serialize>(&self, t: &T) -> Result<Vec<u8>, Error> {
+        bincode::serialize(t).map_err(Error::from)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, b: &[u8]) -> Result<T, Error> {
+        bincode::deserialize(b).map_err(Error::from)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/octet-stream"
+    }
+}
+
+/// Human-readable wire format, at the cost of size. Useful when debugging
+/// a route with `curl`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn serialize<T: Serialize>(&self, t: &T) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(t).map_err(Error::from)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, b: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(b).map_err(Error::from)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+}
+
+/// A middle ground between `Bincode` and `Json`: compact like bincode, but
+/// self-describing enough to decode without knowing the schema ahead of time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn serialize<T: Serialize>(&self, t: &T) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec(t).map_err(Error::from)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, b: &[u8]) -> Result<T, Error> {
+        rmp_serde::from_slice(b).map_err(Error::from)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+}
+
+/// Strip any `;`-delimited parameters (e.g. `; charset=utf-8`) and
+/// lowercase what's left, so a `Content-Type` header matches regardless of
+/// casing or trailing parameters a real HTTP client tacks on.
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Decode a request body according to its `Content-Type`, as the Service
+/// side does to content-negotiate an inbound call. Defaults to `Bincode`
+/// for backward compatibility with callers that don't set a header.
+pub(crate) fn decode_body<T: DeserializeOwned>(content_type: Option<&str>, body: &[u8]) -> Result<T, Error> {
+    match content_type.map(media_type).as_deref() {
+        Some("application/json") => Json.deserialize(body),
+        Some("application/msgpack") => MessagePack.deserialize(body),
+        _ => Bincode.deserialize(body),
+    }
+}
+
+/// Encode a response body the same way the matching request came in, so
+/// the codec choice is symmetric across a round trip.
+pub(crate) fn encode_body<T: Serialize>(content_type: Option<&str>, t: &T) -> Result<(Vec<u8>, &'static str), Error> {
+    match content_type.map(media_type).as_deref() {
+        Some("application/json") => Json.serialize(t).map(|b| (b, Json.content_type())),
+        Some("application/msgpack") => MessagePack.serialize(t).map(|b| (b, MessagePack.content_type())),
+        _ => Bincode.serialize(t).map(|b| (b, Bincode.content_type())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_body_matches_content_type_with_params_and_casing() {
+        let encoded = Json.serialize(&42u32).unwrap();
+        let decoded: u32 = decode_body(Some("Application/JSON; charset=utf-8"), &encoded).unwrap();
+        assert_eq!(decoded, 42);
+    }
+}
@@ -0,0 +1,223 @@
+//! JSON-RPC 2.0 framing for remote actors.
+//!
+//! `JsonRpcHandler<M>` is a sibling of [`HttpHandler`](super::client::HttpHandler)
+//! that frames each call as a JSON-RPC 2.0 request/response object instead of
+//! an opaque bincode blob, so soar services can interoperate with any
+//! JSON-RPC client or server.
+use actix::Addr;
+use actix_web::{client::ClientRequest, HttpMessage};
+use failure::{Error, Fail};
+use futures::{future, Future};
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::get_type;
+use crate::router::DispatchJson;
+use crate::service::*;
+
+/// A JSON-RPC 2.0 request object, per the spec at <https://www.jsonrpc.org/specification>.
+#[derive(Debug, Deserialize, Serialize)]
+struct JsonRpcRequest {
+    jsonrpc: String,
+    method: String,
+    params: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+}
+
+/// A JSON-RPC 2.0 response object. Exactly one of `result`/`error` is set,
+/// unless `id` is absent, in which case the request was a notification.
+#[derive(Debug, Deserialize, Serialize)]
+struct JsonRpcResponse {
+    jsonrpc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<JsonRpcError>,
+    id: Option<u64>,
+}
+
+impl JsonRpcResponse {
+    fn result(id: u64, result: Value) -> Self {
+        JsonRpcResponse { jsonrpc: "2.0".to_string(), result: Some(result), error: None, id: Some(id) }
+    }
+
+    fn error(id: Option<u64>, error: JsonRpcError) -> Self {
+        JsonRpcResponse { jsonrpc: "2.0".to_string(), result: None, error: Some(error), id }
+    }
+}
+
+/// The `{code, message, data}` error object used by JSON-RPC 2.0.
+#[derive(Debug, Deserialize, Serialize, Fail)]
+#[fail(display = "jsonrpc error {}: {}", code, message)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+/// Maps a handler error onto the JSON-RPC error object. The default impl,
+/// for `failure::Error` (what every dispatch path here actually produces),
+/// covers the common case (code `-32000`, the JSON-RPC "Server error"
+/// range); implement this directly for a concrete error type that wants a
+/// more specific code.
+pub trait ErrorLike {
+    fn to_rpc_error(&self) -> JsonRpcError;
+}
+
+impl ErrorLike for Error {
+    fn to_rpc_error(&self) -> JsonRpcError {
+        JsonRpcError {
+            code: -32000,
+            message: self.to_string(),
+            data: None,
+        }
+    }
+}
+
+/// Sends each outgoing call framed as a JSON-RPC 2.0 request, matching the
+/// returned `id` against a monotonic counter and mapping a JSON-RPC error
+/// into a `failure::Error`.
+pub struct JsonRpcHandler<M> {
+    url: Url,
+    next_id: AtomicU64,
+    _msg: PhantomData<M>,
+}
+
+impl<M: SoarMessage> From<Url> for JsonRpcHandler<M> {
+    fn from(url: Url) -> Self {
+        JsonRpcHandler {
+            url,
+            next_id: AtomicU64::new(1),
+            _msg: PhantomData,
+        }
+    }
+}
+
+impl<M: SoarMessage> RequestHandler<M> for JsonRpcHandler<M> {
+    fn handle_request(&mut self, msg: M, _: Addr<Service>) -> RespFuture<M> {
+        let url = self.url.clone();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let method = get_type!(M).to_string();
+        trace!("Making JSON-RPC request {:?} (id {}) to {}", method, id, url);
+
+        let fut = future::result(serde_json::to_value(&msg).map_err(Error::from))
+            .and_then(move |params| {
+                let req = JsonRpcRequest { jsonrpc: "2.0".to_string(), method, params, id: Some(id) };
+                ClientRequest::post(url)
+                    .json(req)
+                    .unwrap()
+                    .send()
+                    .map_err(Error::from)
+                    .and_then(|resp| resp.json::<JsonRpcResponse>().map_err(Error::from))
+            })
+            .and_then(move |resp| {
+                if resp.id != Some(id) {
+                    return future::err(Error::from(JsonRpcError {
+                        code: -32000,
+                        message: "response id did not match request id".into(),
+                        data: None,
+                    }));
+                }
+                if let Some(err) = resp.error {
+                    return future::err(Error::from(err));
+                }
+                match resp.result {
+                    Some(result) => future::result(serde_json::from_value(result).map_err(Error::from)),
+                    None => future::err(Error::from(JsonRpcError {
+                        code: -32000,
+                        message: "response had neither result nor error".into(),
+                        data: None,
+                    })),
+                }
+            });
+        Box::new(fut)
+    }
+}
+
+/// A single inbound JSON-RPC request, or a batch of them. Accepted by the
+/// Service's JSON-RPC endpoint and answered in kind: a batch gets an array
+/// of responses back, preserving id-to-result correspondence and dropping
+/// notifications (requests with no `id`) from the reply.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum JsonRpcBody {
+    Single(JsonRpcRequest),
+    Batch(Vec<JsonRpcRequest>),
+}
+
+/// Parse and dispatch an inbound JSON-RPC request (or batch) against the
+/// current arbiter's `Router`, by `method` name. Used by the Service's
+/// JSON-RPC HTTP endpoint.
+pub(crate) fn handle_jsonrpc(body: JsonRpcBody) -> Box<Future<Item=Option<Value>, Error=Error>> {
+    match body {
+        JsonRpcBody::Single(req) => {
+            let id = req.id;
+            Box::new(dispatch_one(req).map(move |resp| {
+                id.map(|_| serde_json::to_value(resp).expect("JsonRpcResponse always serializes"))
+            }))
+        }
+        JsonRpcBody::Batch(reqs) => {
+            let ids: Vec<_> = reqs.iter().map(|r| r.id).collect();
+            let responses = reqs.into_iter().map(dispatch_one).collect::<Vec<_>>();
+            Box::new(future::join_all(responses).map(move |resps| {
+                let values: Vec<Value> = resps.into_iter().zip(ids.into_iter())
+                    .filter(|(_, id)| id.is_some())
+                    .map(|(resp, _)| serde_json::to_value(resp).expect("JsonRpcResponse always serializes"))
+                    .collect();
+                Some(Value::Array(values))
+            }))
+        }
+    }
+}
+
+fn dispatch_one(req: JsonRpcRequest) -> impl Future<Item=JsonRpcResponse, Error=Error> {
+    let id = req.id;
+    crate::router::send(DispatchJson { method: req.method, params: req.params })
+        .then(move |res| {
+            Ok(match res {
+                Ok(Ok(value)) => id.map(|id| JsonRpcResponse::result(id, value))
+                    .unwrap_or_else(|| JsonRpcResponse::error(None, JsonRpcError {
+                        code: -32600,
+                        message: "notifications are not supported for dispatched calls".into(),
+                        data: None,
+                    })),
+                Ok(Err(e)) => JsonRpcResponse::error(id, e.to_rpc_error()),
+                Err(e) => JsonRpcResponse::error(id, e.to_rpc_error()),
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestServer;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn test_jsonrpc_channel() {
+        init_logger();
+        let mut server = TestServer::new(|app| {
+            app.resource("/test", |r| r.f(|_| {
+                trace!("Received JSON-RPC request! Responding with answer");
+                actix_web::HttpResponse::Ok().json(JsonRpcResponse::result(1, serde_json::json!(TestResponse(138))))
+            }));
+        });
+
+        let url = Url::parse(&server.url("/test")).unwrap();
+        let res = server.execute(futures::future::lazy(|| {
+            let addr = Service::build("jsonrpc_channel_test_client")
+                                        .add_handler(JsonRpcHandler::<TestMessage>::from(url.clone()))
+                                        .address();
+            addr.send(TestMessage(138))
+        })).unwrap();
+        assert_eq!(res.0, 138);
+    }
+}
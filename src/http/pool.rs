@@ -0,0 +1,358 @@
+//! Connection pooling, retry-with-backoff, and circuit-breaking for
+//! [`HttpHandler`](super::client::HttpHandler).
+//!
+//! Without this, every `handle_request` call opens a fresh connection and a
+//! single flapping remote actor stalls every caller. `ResilientClient` keeps
+//! one pooled `ClientConnector` per target host, retries transport errors
+//! (never deserialization errors) with backoff, and trips a circuit breaker
+//! once a target's rolling failure ratio crosses a threshold so callers fail
+//! fast instead of piling up against a target that's down.
+use actix_web::client::{ClientConnector, ClientRequest};
+use failure::{Error, Fail};
+use futures::future::{self, Loop};
+use futures::Future;
+use log::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix::Addr;
+
+/// Tuning knobs for a [`ResilientClient`], passed to
+/// `Service::build().add_http_handler_with_options` to make a remote route
+/// production-safe.
+#[derive(Clone, Copy, Debug)]
+pub struct HttpClientOptions {
+    /// Maximum concurrent connections held open per target host.
+    pub max_conns: usize,
+    /// Number of retries on transport errors, after the initial attempt.
+    pub retries: u32,
+    /// Base delay between retries; attempt `n` waits `backoff * n`.
+    pub backoff: Duration,
+    /// Failure ratio (0.0-1.0) within the most recent `window` requests
+    /// above which the breaker trips.
+    pub failure_threshold: f64,
+    /// Number of most-recent requests the failure ratio is computed over.
+    /// The breaker only evaluates once this many outcomes have been
+    /// recorded, so a target isn't tripped on a handful of early failures.
+    pub window: usize,
+    /// How long the breaker stays open before allowing a half-open probe.
+    pub cooldown: Duration,
+}
+
+impl Default for HttpClientOptions {
+    fn default() -> Self {
+        HttpClientOptions {
+            max_conns: 16,
+            retries: 2,
+            backoff: Duration::from_millis(100),
+            failure_threshold: 0.5,
+            window: 10,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default, Fail)]
+#[fail(display = "circuit open for this target; failing fast")]
+pub struct CircuitOpen;
+
+/// One target host's pooled connector and breaker state.
+struct Target {
+    connector: Addr<ClientConnector>,
+    breaker: Breaker,
+}
+
+enum Breaker {
+    /// `outcomes` holds the most recent `HttpClientOptions::window` calls
+    /// (`true` = success), oldest first, so the failure ratio only ever
+    /// reflects recent traffic instead of accumulating for the lifetime of
+    /// the target.
+    Closed { outcomes: VecDeque<bool> },
+    Open { opened_at: Instant },
+    HalfOpen,
+}
+
+/// A pooled, resilient HTTP client, modeled on bb8-style per-host pooling:
+/// one connector and one breaker per host it has seen. Each
+/// `HttpHandler::with_options` call creates its own `ResilientClient`, so
+/// handlers do not share a pool or breaker state, even for the same host.
+pub struct ResilientClient {
+    options: HttpClientOptions,
+    targets: Mutex<HashMap<String, Target>>,
+}
+
+impl ResilientClient {
+    pub fn new(options: HttpClientOptions) -> Self {
+        ResilientClient {
+            options,
+            targets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn connector_for(&self, host: &str) -> Result<Addr<ClientConnector>, Error> {
+        let mut targets = self.targets.lock().expect("ResilientClient pool lock poisoned");
+        if let Some(target) = targets.get_mut(host) {
+            if self.check_breaker(host, &mut target.breaker) {
+                return Err(Error::from(CircuitOpen::default()));
+            }
+            return Ok(target.connector.clone());
+        }
+        let connector = ClientConnector::default()
+            .max_conns(self.options.max_conns)
+            .start();
+        targets.insert(host.to_string(), Target {
+            connector: connector.clone(),
+            breaker: Breaker::Closed { outcomes: VecDeque::new() },
+        });
+        Ok(connector)
+    }
+
+    /// Whether a call to `host` should fail fast with `CircuitOpen`. Takes
+    /// `&mut Breaker` and flips `Open` to `HalfOpen` itself, right here under
+    /// the `targets` lock, rather than leaving that transition for `record`
+    /// to make later: if the flip happened post-completion instead, every
+    /// caller that observed an aged-out `Open` breaker before the probe
+    /// finished would also read "not blocked" and pile onto the target at
+    /// once. Gating the transition at check time means only the caller that
+    /// performs the flip gets through as the probe; everyone else still sees
+    /// `HalfOpen` as blocking until that probe resolves.
+    fn check_breaker(&self, host: &str, breaker: &mut Breaker) -> bool {
+        match breaker {
+            Breaker::Closed { .. } => false,
+            Breaker::HalfOpen => true,
+            Breaker::Open { opened_at } if opened_at.elapsed() >= self.options.cooldown => {
+                trace!("Circuit for {:?} entering half-open probe", host);
+                *breaker = Breaker::HalfOpen;
+                false
+            }
+            Breaker::Open { .. } => true,
+        }
+    }
+
+    fn record(&self, host: &str, success: bool) {
+        let mut targets = self.targets.lock().expect("ResilientClient pool lock poisoned");
+        let target = match targets.get_mut(host) {
+            Some(t) => t,
+            None => return,
+        };
+        // Matched by value (via `mem::replace`) rather than by reference so
+        // the `Closed` arm below can reuse `outcomes`'s allocation instead
+        // of cloning it.
+        let current = std::mem::replace(&mut target.breaker, Breaker::HalfOpen);
+        target.breaker = match (current, success) {
+            (Breaker::HalfOpen, true) => {
+                trace!("Circuit for {:?} closed after successful probe", host);
+                Breaker::Closed { outcomes: VecDeque::new() }
+            }
+            (Breaker::HalfOpen, false) => Breaker::Open { opened_at: Instant::now() },
+            // The Open -> HalfOpen flip happens in `check_breaker`, atomically
+            // with the check itself; by the time a result comes back here the
+            // breaker is already HalfOpen for whichever caller won that race;
+            // callers that lose it fail fast in `connector_for` and never get
+            // here. An `Open` breaker seen here belongs to a call admitted
+            // while the breaker was still `Closed` that finished after another
+            // call tripped it: its outcome is dropped, cooldown clock untouched.
+            (Breaker::Open { opened_at }, _) => Breaker::Open { opened_at },
+            (Breaker::Closed { mut outcomes }, success) => {
+                outcomes.push_back(success);
+                while outcomes.len() > self.options.window {
+                    outcomes.pop_front();
+                }
+                let total = outcomes.len();
+                let failures = outcomes.iter().filter(|success| !**success).count();
+                if total >= self.options.window && (failures as f64 / total as f64) > self.options.failure_threshold {
+                    debug!("Circuit for {:?} opened: {}/{} of the last {} requests failing", host, failures, total, self.options.window);
+                    Breaker::Open { opened_at: Instant::now() }
+                } else {
+                    Breaker::Closed { outcomes }
+                }
+            }
+        };
+    }
+
+    /// Post `body` to `url`, retrying transport errors up to `options.retries`
+    /// times with backoff, and failing fast with `CircuitOpen` if the target's
+    /// breaker is tripped. Takes `&Arc<Self>` so the returned future can hold
+    /// the client and record the outcome against the target's breaker once
+    /// the call completes.
+    pub fn post(self: &Arc<Self>, url: url::Url, content_type: &'static str, body: Vec<u8>) -> Box<Future<Item=Vec<u8>, Error=Error> + Send> {
+        let host = url.host_str().unwrap_or("").to_string();
+        let connector = match self.connector_for(&host) {
+            Ok(c) => c,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let retries = self.options.retries;
+        let backoff = self.options.backoff;
+        let host_for_record = host.clone();
+        let this = self.clone();
+
+        let fut = future::loop_fn(0u32, move |tries| {
+            let url = url.clone();
+            let body = body.clone();
+            let connector = connector.clone();
+            ClientRequest::post(url)
+                .header("Content-Type", content_type)
+                .header("Accept", content_type)
+                .with_connector(connector)
+                .body(body)
+                .unwrap()
+                .send()
+                .map_err(Error::from)
+                .and_then(|resp| resp.body().map_err(Error::from))
+                .then(move |res| -> Box<Future<Item=Loop<Vec<u8>, u32>, Error=Error> + Send> {
+                    match res {
+                        Ok(body) => Box::new(future::ok(Loop::Break(body.to_vec()))),
+                        Err(e) => {
+                            if tries >= retries {
+                                Box::new(future::err(e))
+                            } else {
+                                let wait = backoff * (tries + 1);
+                                trace!("Transport error, retrying in {:?}: {}", wait, e);
+                                Box::new(
+                                    tokio_timer::Delay::new(Instant::now() + wait)
+                                        .then(move |_| Ok(Loop::Continue(tries + 1)))
+                                )
+                            }
+                        }
+                    }
+                })
+        });
+
+        Box::new(fut.then(move |res| {
+            this.record(&host_for_record, res.is_ok());
+            res
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestServer;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    /// No retries, a 1ms backoff and a 50ms cooldown, so a circuit trips
+    /// and recovers quickly; the rolling window is the default's 10
+    /// requests.
+    fn test_options() -> HttpClientOptions {
+        HttpClientOptions {
+            max_conns: 1,
+            retries: 0,
+            backoff: Duration::from_millis(1),
+            failure_threshold: 0.5,
+            window: 10,
+            cooldown: Duration::from_millis(50),
+        }
+    }
+
+    /// Drives `client.post` against a dropped `TestServer`'s address (so
+    /// every attempt is a transport failure) on a fresh `TestServer`'s
+    /// reactor, `count` times, discarding the results.
+    fn fail_n_times(server: &mut TestServer, client: &Arc<ResilientClient>, url: &url::Url, count: usize) {
+        for _ in 0..count {
+            let client = client.clone();
+            let url = url.clone();
+            let _ = server.execute(futures::future::lazy(move || client.post(url, "application/octet-stream", vec![])));
+        }
+    }
+
+    #[test]
+    fn test_circuit_opens_after_failure_threshold() {
+        init_logger();
+        // Nothing is listening at this address once the `TestServer` that
+        // handed it out is dropped, so every request against it is a
+        // transport failure.
+        let dead = TestServer::new(|_| {});
+        let url = url::Url::parse(&dead.url("/unreachable")).unwrap();
+        drop(dead);
+
+        let client = Arc::new(ResilientClient::new(test_options()));
+        let mut server = TestServer::new(|_| {});
+
+        // `Closed` only evaluates `failure_threshold` once `options.window`
+        // outcomes have been recorded; send enough failures to fill it.
+        fail_n_times(&mut server, &client, &url, test_options().window);
+
+        let res = server.execute(futures::future::lazy({
+            let client = client.clone();
+            let url = url.clone();
+            move || client.post(url, "application/octet-stream", vec![])
+        }));
+        match res {
+            Err(e) => assert!(e.downcast_ref::<CircuitOpen>().is_some(), "expected CircuitOpen, got {:?}", e),
+            Ok(_) => panic!("expected the circuit to be open by now"),
+        }
+    }
+
+    #[test]
+    fn test_half_open_admits_a_single_probe() {
+        init_logger();
+        let dead = TestServer::new(|_| {});
+        let url = url::Url::parse(&dead.url("/unreachable")).unwrap();
+        drop(dead);
+
+        let client = Arc::new(ResilientClient::new(test_options()));
+        let mut server = TestServer::new(|_| {});
+        fail_n_times(&mut server, &client, &url, test_options().window);
+
+        // Confirm the circuit is open before the cooldown elapses.
+        let res = server.execute(futures::future::lazy({
+            let client = client.clone();
+            let url = url.clone();
+            move || client.post(url, "application/octet-stream", vec![])
+        }));
+        assert!(res.err().unwrap().downcast_ref::<CircuitOpen>().is_some());
+
+        std::thread::sleep(test_options().cooldown);
+
+        // Two callers race to enter past cooldown: only one should see the
+        // breaker as HalfOpen-and-admitted (and become the probe); the other
+        // must still see CircuitOpen rather than both piling onto the target.
+        // `connector_for` is synchronous, so no reactor is needed to call it.
+        let first = client.connector_for(url.host_str().unwrap());
+        let second = client.connector_for(url.host_str().unwrap());
+        assert!(first.is_ok(), "the probe caller should be admitted");
+        assert!(second.is_err(), "a second caller must not also be admitted as a probe");
+    }
+
+    #[test]
+    fn test_closed_breaker_window_ages_out_old_failures() {
+        init_logger();
+        let options = HttpClientOptions { window: 4, ..test_options() };
+        let client = ResilientClient::new(options);
+        let server = TestServer::new(|_| {});
+
+        // Seed a target directly, the way `connector_for` would on first
+        // use, so `record` below has something to update without a real
+        // network round trip.
+        server.execute(future::lazy(|| {
+            client.targets.lock().unwrap().insert("host".to_string(), Target {
+                connector: ClientConnector::default().start(),
+                breaker: Breaker::Closed { outcomes: VecDeque::new() },
+            });
+            future::ok::<(), ()>(())
+        })).unwrap();
+
+        client.record("host", false);
+        client.record("host", false);
+        // Four more outcomes fill a window of 4, pushing both failures out.
+        client.record("host", true);
+        client.record("host", true);
+        client.record("host", true);
+        client.record("host", true);
+        client.record("host", false);
+
+        // Latest window is [true, true, true, false] -> 1/4 = 0.25, under
+        // the 0.5 threshold. If the old failures hadn't aged out of the
+        // window, `outcomes` would hold all 7 results (3 of them failures)
+        // instead of the latest 4.
+        let targets = client.targets.lock().unwrap();
+        match &targets.get("host").unwrap().breaker {
+            Breaker::Closed { outcomes } => assert_eq!(outcomes.len(), 4, "window should cap at 4 outcomes"),
+            _ => panic!("expected the breaker to remain closed"),
+        }
+    }
+}
@@ -5,18 +5,44 @@ use futures::{future, Future};
 use log::*;
 use serde::{de::DeserializeOwned, Serialize};
 use serde_derive::{Deserialize, Serialize};
+use serde_json;
 
+use std::any::Any;
+use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::sync::Arc;
 
 use crate::get_type;
+use crate::layer::Layer;
+
+/// A request/response pair, erased to `serde_json::Value`, used to dispatch
+/// a method-name-addressed call (e.g. JSON-RPC) onto a statically-typed
+/// route without the caller knowing `M`. `Arc` (rather than `Box`) so
+/// `Router::wrap_in_layers` can clone one out of the routing table and move
+/// it into the innermost step of the layer chain.
+type JsonDispatcher = Arc<dyn Fn(serde_json::Value) -> Box<Future<Item=serde_json::Value, Error=Error>> + Send + Sync>;
+
+/// Same idea as `JsonDispatcher`, but for the codec-negotiated (non-JSON-RPC)
+/// HTTP path: raw bytes in, raw bytes + the `Content-Type` to answer with
+/// out, content-negotiated per `crate::http::codec`.
+type BytesDispatcher = Arc<dyn Fn(Option<String>, Vec<u8>) -> Box<Future<Item=(Vec<u8>, &'static str), Error=Error>> + Send + Sync>;
 
 /// A lookup from `Message` types to addresses to request handlers.
-/// This is encapsulated by an `AnyMap`, but the methods `insert_handler`, 
+/// This is encapsulated by an `AnyMap`, but the methods `insert_handler`,
 /// and `insert_handler_fut` ensure that only `Route<M: SoarMessage>`s are
 /// actually added (or retrieved).
 pub struct Router {
     pub routes: AnyMap,
+    /// Mirror of `routes`, keyed by the message type's `get_type!` name
+    /// instead of its Rust type, so that string-addressed callers (the
+    /// JSON-RPC handler) can dispatch without a generic parameter.
+    json_routes: HashMap<String, JsonDispatcher>,
+    /// Same idea as `json_routes`, for the codec-negotiated HTTP path.
+    bytes_routes: HashMap<String, BytesDispatcher>,
+    /// Middleware wrapping every dispatch, in registration order (the
+    /// first-registered layer is outermost). See [`crate::layer`].
+    layers: Vec<Arc<dyn Layer>>,
 }
 
 impl std::default::Default for Router {
@@ -77,23 +103,103 @@ impl Router {
     pub fn new() -> Self {
         Router {
             routes: AnyMap::new(),
+            json_routes: HashMap::new(),
+            bytes_routes: HashMap::new(),
+            layers: Vec::new(),
         }
     }
 
+    /// Register a layer to run on every message dispatched through this
+    /// router, outermost-first in registration order. `ServiceBuilder::wrap`
+    /// does not call this; it goes through [`wrap_layer`] instead.
+    pub fn wrap(&mut self, layer: impl Layer) {
+        self.layers.push(Arc::new(layer));
+    }
+
     /// Add this address into the routing table.
     pub fn insert_handler<M: SoarMessage>(&mut self, handler: Recipient<M>) {
+        self.json_routes.insert(get_type!(M).to_string(), json_dispatcher(handler.clone()));
+        self.bytes_routes.insert(get_type!(M).to_string(), bytes_dispatcher(handler.clone()));
         self.routes.insert(
             Route::Done(handler)
         );
     }
 
+    /// Add a not-yet-resolved route into the routing table, the same way
+    /// `insert_handler` does for one that's already resolved: `get_recipient`
+    /// already queues a typed `send` onto `fut` via `Route::Pending`, so
+    /// `dispatch_json`/`dispatch_bytes` need their own pending-aware
+    /// dispatchers rather than a plain `RouterError` until `fut` resolves and
+    /// `insert_handler` overwrites these entries with the real ones.
+    fn insert_pending_handler<M: SoarMessage>(&mut self, fut: PendingRoute<M>) {
+        self.json_routes.insert(get_type!(M).to_string(), json_dispatcher_pending(fut.clone()));
+        self.bytes_routes.insert(get_type!(M).to_string(), bytes_dispatcher_pending(fut.clone()));
+        self.routes.insert(Route::Pending(fut));
+    }
+
     /// Delete a handler from the routing table.
     pub fn remove_handler<M>(&mut self)
         where M: SoarMessage
     {
+        self.json_routes.remove(get_type!(M));
+        self.bytes_routes.remove(get_type!(M));
         self.routes.remove::<M>();
     }
 
+    /// Dispatch a request by method name, as used by the JSON-RPC handler,
+    /// rather than by the static type `M`. `params` is serialized/deserialized
+    /// through `M`/`M::Response` the same way a typed route would be, and the
+    /// call is wrapped in `self.layers` the same way a typed route is.
+    pub fn dispatch_json(&self, method: &str, params: serde_json::Value) -> Box<Future<Item=serde_json::Value, Error=Error>> {
+        let dispatch = match self.json_routes.get(method) {
+            Some(dispatch) => dispatch.clone(),
+            None => return Box::new(future::err(Error::from(RouterError::default()))),
+        };
+        let next: crate::layer::Next = Box::new(move |msg: Box<dyn Any + Send>| {
+            let params = *msg.downcast::<serde_json::Value>().expect("Router erasure invariant violated");
+            let fut = dispatch(params).map(|resp| Box::new(resp) as Box<dyn Any + Send>);
+            Box::new(fut) as crate::layer::RespFuture
+        });
+        let boxed: Box<dyn Any + Send> = Box::new(params);
+        let fut = self.wrap_in_layers(method, boxed, next)
+            .map(|resp| *resp.downcast::<serde_json::Value>().expect("Router erasure invariant violated"));
+        Box::new(fut)
+    }
+
+    /// Dispatch a request by method name with the body framed as raw bytes
+    /// instead of `serde_json::Value`, content-negotiated by `content_type`
+    /// per `crate::http::codec`. Used by the Service's `/rpc/{method}`
+    /// endpoint; wrapped in `self.layers` the same way `dispatch_json` is.
+    pub fn dispatch_bytes(&self, method: &str, content_type: Option<String>, body: Vec<u8>) -> Box<Future<Item=(Vec<u8>, &'static str), Error=Error>> {
+        let dispatch = match self.bytes_routes.get(method) {
+            Some(dispatch) => dispatch.clone(),
+            None => return Box::new(future::err(Error::from(RouterError::default()))),
+        };
+        let next: crate::layer::Next = Box::new(move |msg: Box<dyn Any + Send>| {
+            let (content_type, body) = *msg.downcast::<(Option<String>, Vec<u8>)>().expect("Router erasure invariant violated");
+            let fut = dispatch(content_type, body).map(|resp| Box::new(resp) as Box<dyn Any + Send>);
+            Box::new(fut) as crate::layer::RespFuture
+        });
+        let boxed: Box<dyn Any + Send> = Box::new((content_type, body));
+        let fut = self.wrap_in_layers(method, boxed, next)
+            .map(|resp| *resp.downcast::<(Vec<u8>, &'static str)>().expect("Router erasure invariant violated"));
+        Box::new(fut)
+    }
+
+    /// Fold `self.layers` around `next`, outermost-first, exactly as the
+    /// typed `Handler<M>` impl does below — shared so every dispatch path
+    /// (typed, JSON-RPC, and the codec-negotiated bytes path) runs through
+    /// the same middleware instead of `json_dispatcher`/`bytes_dispatcher`
+    /// calling their recipient directly.
+    fn wrap_in_layers(&self, type_name: &str, msg: Box<dyn Any + Send>, next: crate::layer::Next) -> crate::layer::RespFuture {
+        let type_name = type_name.to_string();
+        let chain = self.layers.iter().cloned().rev().fold(next, |next, layer| {
+            let type_name = type_name.clone();
+            Box::new(move |msg: Box<dyn Any + Send>| layer.call(&type_name, msg, next)) as crate::layer::Next
+        });
+        chain(msg)
+    }
+
 
     /// Get the handler identified by the generic type parameter `M`.
     pub fn get_recipient<M>(&self) -> impl Future<Item=Recipient<M>, Error=()>
@@ -177,7 +283,7 @@ where M: SoarMessage,
             recip
         });
         ctxt.spawn(fut.map(|_, _ ,_| ()).map_err(|_, _, _| ()));
-        self.routes.insert(Route::Pending(shared));
+        self.insert_pending_handler::<M>(shared);
     }
 }
 
@@ -201,6 +307,120 @@ where M: SoarMessage,
     }
 }
 
+/// Dispatch a method-name-addressed call (see [`Router::dispatch_json`]).
+/// Used by the JSON-RPC handler, which only knows the method name at
+/// runtime and can't name `M` to go through the usual `Handler<M>` impl.
+pub(crate) struct DispatchJson {
+    pub method: String,
+    pub params: serde_json::Value,
+}
+
+impl Message for DispatchJson {
+    type Result = Result<serde_json::Value, Error>;
+}
+
+impl Handler<DispatchJson> for Router {
+    type Result = ResponseFuture<serde_json::Value, Error>;
+
+    fn handle(&mut self, msg: DispatchJson, _ctxt: &mut Context<Self>) -> Self::Result {
+        self.dispatch_json(&msg.method, msg.params)
+    }
+}
+
+/// Register a layer on the current arbiter's `Router`. Used by `ServiceBuilder::wrap`.
+pub(crate) struct WrapLayer(pub Arc<dyn Layer>);
+
+impl Message for WrapLayer {
+    type Result = ();
+}
+
+impl Handler<WrapLayer> for Router {
+    type Result = ();
+
+    fn handle(&mut self, msg: WrapLayer, _ctxt: &mut Context<Self>) {
+        self.layers.push(msg.0);
+    }
+}
+
+/// Register `layer` to run on every message dispatched through the current
+/// arbiter's `Router`. Used by `ServiceBuilder::wrap`.
+pub fn wrap_layer(layer: impl Layer) {
+    send_spawn(WrapLayer(Arc::new(layer)));
+}
+
+/// Dispatch a method-name-addressed call with a raw-bytes body (see
+/// [`Router::dispatch_bytes`]). Used by the Service's `/rpc/{method}` endpoint.
+pub(crate) struct DispatchBytes {
+    pub method: String,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Message for DispatchBytes {
+    type Result = Result<(Vec<u8>, &'static str), Error>;
+}
+
+impl Handler<DispatchBytes> for Router {
+    type Result = ResponseFuture<(Vec<u8>, &'static str), Error>;
+
+    fn handle(&mut self, msg: DispatchBytes, _ctxt: &mut Context<Self>) -> Self::Result {
+        self.dispatch_bytes(&msg.method, msg.content_type, msg.body)
+    }
+}
+
+/// Build a type-erased dispatcher for `M`: deserialize `params` into `M`,
+/// send it to `handler`, and serialize the response back to JSON.
+fn json_dispatcher<M: SoarMessage>(handler: Recipient<M>) -> JsonDispatcher {
+    Arc::new(move |params: serde_json::Value| {
+        let handler = handler.clone();
+        let fut = future::result(serde_json::from_value::<M>(params).map_err(Error::from))
+            .and_then(move |msg| handler.send(msg).map_err(Error::from))
+            .and_then(|resp| future::result(serde_json::to_value(resp).map_err(Error::from)));
+        Box::new(fut) as Box<Future<Item=serde_json::Value, Error=Error>>
+    })
+}
+
+/// Build a type-erased dispatcher for `M`: decode `body` into `M` per
+/// `content_type`, send it to `handler`, and encode the response the same way.
+fn bytes_dispatcher<M: SoarMessage>(handler: Recipient<M>) -> BytesDispatcher {
+    Arc::new(move |content_type: Option<String>, body: Vec<u8>| {
+        let handler = handler.clone();
+        let encode_content_type = content_type.clone();
+        let fut = future::result(crate::http::codec::decode_body::<M>(content_type.as_ref().map(String::as_str), &body))
+            .and_then(move |msg| handler.send(msg).map_err(Error::from))
+            .and_then(move |resp| future::result(crate::http::codec::encode_body(encode_content_type.as_ref().map(String::as_str), &resp)));
+        Box::new(fut) as Box<Future<Item=(Vec<u8>, &'static str), Error=Error>>
+    })
+}
+
+/// Same as `json_dispatcher`, but for a route that hasn't resolved yet:
+/// each call clones `fut` and waits on it before sending, mirroring how
+/// `get_recipient` handles `Route::Pending` for the typed dispatch path.
+fn json_dispatcher_pending<M: SoarMessage>(fut: PendingRoute<M>) -> JsonDispatcher {
+    Arc::new(move |params: serde_json::Value| {
+        let fut = fut.clone().map_err(|_| Error::from(RouterError::default()));
+        let msg = future::result(serde_json::from_value::<M>(params).map_err(Error::from));
+        let dispatch = msg.join(fut)
+            .and_then(|(msg, recip)| recip.deref().clone().send(msg).map_err(Error::from))
+            .and_then(|resp| future::result(serde_json::to_value(resp).map_err(Error::from)));
+        Box::new(dispatch) as Box<Future<Item=serde_json::Value, Error=Error>>
+    })
+}
+
+/// Same as `bytes_dispatcher`, but for a route that hasn't resolved yet;
+/// see `json_dispatcher_pending`.
+fn bytes_dispatcher_pending<M: SoarMessage>(fut: PendingRoute<M>) -> BytesDispatcher {
+    Arc::new(move |content_type: Option<String>, body: Vec<u8>| {
+        let fut = fut.clone().map_err(|_| Error::from(RouterError::default()));
+        let encode_content_type = content_type.clone();
+        let msg = future::result(crate::http::codec::decode_body::<M>(content_type.as_ref().map(String::as_str), &body));
+        let dispatch = msg.join(fut)
+            .and_then(|(msg, recip)| recip.deref().clone().send(msg).map_err(Error::from))
+            .and_then(move |resp| future::result(crate::http::codec::encode_body(encode_content_type.as_ref().map(String::as_str), &resp)));
+        Box::new(dispatch) as Box<Future<Item=(Vec<u8>, &'static str), Error=Error>>
+    })
+}
+
 #[derive(Default, Deserialize, Serialize, Fail, Debug)]
 #[fail(display = "routing error found")]
 /// `Router` fails when there is no known handler for a given message.
@@ -212,9 +432,24 @@ impl<M> Handler<M> for Router
     type Result = SoarResponse<M>;
 
     fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
+        let type_name = get_type!(M);
         let handler = self.get_recipient::<M>()
                           .map_err(|_| Error::from(RouterError::default()));
-        SoarResponse(Box::new(handler.and_then(|h| h.send(msg).map_err(Error::from))))
+
+        // The innermost step of the chain: downcast back to `M`, send to
+        // the resolved recipient, and erase the response to `Any` so every
+        // layer above can be agnostic to `M`.
+        let dispatch: crate::layer::Next = Box::new(move |msg: Box<dyn Any + Send>| {
+            let msg = *msg.downcast::<M>().expect("Router erasure invariant violated");
+            let fut = handler.and_then(move |h| h.send(msg).map_err(Error::from))
+                              .map(|resp| Box::new(resp) as Box<dyn Any + Send>);
+            Box::new(fut) as crate::layer::RespFuture
+        });
+
+        let boxed_msg: Box<dyn Any + Send> = Box::new(msg);
+        let fut = self.wrap_in_layers(type_name, boxed_msg, dispatch)
+            .map(|resp| *resp.downcast::<M::Response>().expect("Router erasure invariant violated"));
+        SoarResponse(Box::new(fut))
     }
 }
 
@@ -271,20 +506,20 @@ pub fn add_route<M, R>(handler: R)
 /// Set the completion of the future to handle messages of type `M`.
 /// Any messages for this address in the meantime will be chained
 /// onto the future.
-// pub fn add_route_fut<M, R, F>(fut: F)
-//     where M: SoarMessage,
-//           R: Into<Recipient<M>>,
-//           F: 'static + Future<Item=R, Error=()> + Send,
-// {
-//     send_spawn(AddRouteFuture { fut: fut.map(|r| r.into()) });
-// }
+pub fn add_route_fut<M, R, F>(fut: F)
+    where M: SoarMessage,
+          R: 'static + Into<Recipient<M>>,
+          F: 'static + Future<Item=R, Error=()> + Send,
+{
+    send_spawn(AddRouteFuture { fut: fut.map(|r| r.into()) });
+}
 
 /// Delete the route
-// pub fn del_route<M>()
-//     where M: SoarMessage
-// {
-//     send_spawn(RemoveRoute(std::marker::PhantomData::<M>));
-// }
+pub fn del_route<M>()
+    where M: SoarMessage
+{
+    send_spawn(RemoveRoute(std::marker::PhantomData::<M>));
+}
 
 fn send_spawn<M>(msg: M)
     where Router: Handler<M>,
@@ -305,4 +540,70 @@ pub fn send<M>(msg: M) -> impl Future<Item=M::Result, Error=Error>
 {
     Arbiter::registry().get::<Router>().send(msg)
         .map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestServer;
+    use futures::sync::oneshot;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn test_add_route_fut_queues_during_pending_window() {
+        init_logger();
+        let server = TestServer::new(|_| {});
+        let res = server.execute(future::lazy(|| {
+            let (tx, rx) = oneshot::channel::<Recipient<TestMessage>>();
+            add_route_fut::<TestMessage, _, _>(rx.map_err(|_| ()));
+
+            // Sent while the route is still pending: this should queue onto
+            // the future rather than failing with `RouterError`.
+            let pending = send(TestMessage(7));
+
+            let handler = TestHandler::default().start();
+            tx.send(handler.recipient()).ok();
+
+            pending
+        })).unwrap();
+        assert_eq!(res.0, 7);
+    }
+
+    #[test]
+    fn test_dispatch_json_queues_during_pending_window() {
+        init_logger();
+        let server = TestServer::new(|_| {});
+        let res = server.execute(future::lazy(|| {
+            let (tx, rx) = oneshot::channel::<Recipient<TestMessage>>();
+            add_route_fut::<TestMessage, _, _>(rx.map_err(|_| ()));
+
+            // Sent while the route is still pending: dispatch_json should
+            // queue onto the pending future rather than failing with
+            // RouterError, just like the typed path does.
+            let pending = send(DispatchJson {
+                method: get_type!(TestMessage).to_string(),
+                params: serde_json::to_value(TestMessage(7)).unwrap(),
+            });
+
+            let handler = TestHandler::default().start();
+            tx.send(handler.recipient()).ok();
+
+            pending
+        })).unwrap().unwrap();
+        let resp: TestResponse = serde_json::from_value(res).unwrap();
+        assert_eq!(resp.0, 7);
+    }
+
+    #[test]
+    fn test_del_route_removes_handler() {
+        init_logger();
+        let server = TestServer::new(|_| {});
+        let res = server.execute(future::lazy(|| {
+            add_route::<TestMessage, _>(TestHandler::default().start());
+            del_route::<TestMessage>();
+            send(TestMessage(7))
+        }));
+        assert!(res.is_err(), "expected RouterError after del_route, got {:?}", res);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,410 @@
+//! The `Service` facade: a friendly builder for registering handlers (local
+//! actors or remote calls alike) into the current arbiter's `Router`, plus
+//! the HTTP resources a `Service` serves over the network.
+//!
+//! `Service` itself holds no routes — the `Router` (see [`crate::router`])
+//! already does that, arbiter-wide. `Service` just gives callers an
+//! `Addr<Service>` to send `SoarMessage`s to, and gives registration a
+//! single place (`Service::build(name)...address()`) to read top to bottom.
+use ::actix::dev::*;
+use actix_web::{error, Error as ActixError, HttpMessage, HttpRequest, HttpResponse};
+use anymap::AnyMap;
+use failure::Error;
+use futures::{future, Future};
+use log::*;
+use url::Url;
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::extract::State;
+use crate::http::client::HttpHandler;
+use crate::http::codec::Bincode;
+use crate::http::jsonrpc::{handle_jsonrpc, JsonRpcBody};
+use crate::http::pool::HttpClientOptions;
+use crate::layer::Layer;
+use crate::router::{add_route, DispatchBytes, SoarMessage, SoarResponse};
+
+/// The response future a [`RequestHandler`] returns for message `M`: the
+/// same shape `Router` itself expects back from a local `Handler<M>`, so a
+/// remote handler slots into the routing table exactly like a local actor.
+pub type RespFuture<M> = Box<dyn Future<Item=<M as Message>::Result, Error=Error>>;
+
+/// Handles a message `M` by some means other than a hand-written local
+/// `Handler<M>` actor — typically a remote call (`HttpHandler`,
+/// `JsonRpcHandler`). Registered via [`ServiceBuilder::add_handler`], which
+/// wraps it in a [`RemoteActor`] so it can be added to the routing table
+/// the same way `add_route` adds a local actor.
+pub trait RequestHandler<M: SoarMessage>: 'static {
+    fn handle_request(&mut self, msg: M, service: Addr<Service>) -> RespFuture<M>;
+}
+
+/// A thin facade over the current arbiter's `Router`. `Service::build`
+/// registers handlers into the routing table; the resulting `Addr<Service>`
+/// forwards any `SoarMessage` sent to it onto whatever was registered for
+/// that message's type, so a caller holding `Addr<Service>` doesn't need to
+/// know whether the route behind it is local or remote.
+///
+/// `states` is the backing store for the `State<S>` extractor (see
+/// [`crate::extract`]): at most one `Arc<S>` per type `S`, set once via
+/// `ServiceBuilder::with_state` and shared by every `add_fn_handler`
+/// registered for that `S` afterwards, the same way `Router::routes` is a
+/// single `AnyMap` shared by every `add_route` call.
+pub struct Service {
+    name: String,
+    states: AnyMap,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Service { name: "unnamed-service".to_string(), states: AnyMap::new() }
+    }
+}
+
+impl Supervised for Service {}
+
+impl ArbiterService for Service {}
+
+impl Actor for Service {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        trace!("Service {:?} started on arbiter: {}", self.name, Arbiter::name());
+    }
+}
+
+impl<M: SoarMessage> Handler<M> for Service {
+    type Result = SoarResponse<M>;
+
+    fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
+        SoarResponse(Box::new(crate::router::send(msg)))
+    }
+}
+
+/// Adapts a [`RequestHandler<M>`] into a regular `Handler<M>` actor, so it
+/// can be added to the routing table via the usual `add_route`.
+struct RemoteActor<M: SoarMessage, H: RequestHandler<M>> {
+    handler: H,
+    service: Addr<Service>,
+    _msg: PhantomData<M>,
+}
+
+impl<M, H> RemoteActor<M, H>
+    where M: SoarMessage,
+          H: RequestHandler<M>,
+{
+    fn new(handler: H, service: Addr<Service>) -> Self {
+        RemoteActor { handler, service, _msg: PhantomData }
+    }
+}
+
+impl<M, H> Actor for RemoteActor<M, H>
+    where M: SoarMessage,
+          H: RequestHandler<M>,
+{
+    type Context = Context<Self>;
+}
+
+impl<M, H> Handler<M> for RemoteActor<M, H>
+    where M: SoarMessage,
+          H: RequestHandler<M>,
+{
+    type Result = SoarResponse<M>;
+
+    fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
+        SoarResponse(self.handler.handle_request(msg, self.service.clone()))
+    }
+}
+
+/// Wrap `handler` in a `RemoteActor` on the current arbiter and return its
+/// `Recipient<M>`, without adding it to the routing table. Used by
+/// `resolve_remote` (see [`crate::http::client`]), which hands the result to
+/// `add_route_fut` once a future address resolves.
+pub fn into_recipient<M, H>(handler: H) -> Recipient<M>
+    where M: SoarMessage,
+          H: RequestHandler<M>,
+{
+    let service = Arbiter::registry().get::<Service>();
+    RemoteActor::new(handler, service).start().recipient()
+}
+
+/// Instruct the `Service` to store `state` as the shared `State<S>` for
+/// this arbiter. Overwrites any previous state for this `S`.
+struct SetState<S: 'static>(Arc<S>);
+
+impl<S: 'static> Message for SetState<S> {
+    type Result = ();
+}
+
+impl<S: 'static> Handler<SetState<S>> for Service {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetState<S>, _ctxt: &mut Context<Self>) {
+        self.states.insert(msg.0);
+    }
+}
+
+/// Fetch the state registered with `SetState<S>`, if any.
+struct GetState<S: 'static>(PhantomData<S>);
+
+impl<S: 'static> Message for GetState<S> {
+    type Result = Option<Arc<S>>;
+}
+
+impl<S: 'static + Send + Sync> Handler<GetState<S>> for Service {
+    type Result = MessageResult<GetState<S>>;
+
+    fn handle(&mut self, _msg: GetState<S>, _ctxt: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.states.get::<Arc<S>>().cloned())
+    }
+}
+
+/// Register `state` as this arbiter's shared `State<S>`, read by every
+/// `add_fn_handler` registered for `S` afterwards (see
+/// [`ServiceBuilder::with_state`]).
+fn set_state<S: 'static + Send + Sync>(state: S) {
+    let service = Arbiter::registry().get::<Service>();
+    Arbiter::spawn(service.send(SetState(Arc::new(state))).map(|_| ()).map_err(|_| ()));
+}
+
+/// Resolve to the state registered via `set_state` for `S`, queuing behind
+/// the `Service` actor's mailbox the same way any other message would.
+fn get_state<S: 'static + Send + Sync>() -> impl Future<Item=Option<Arc<S>>, Error=()> {
+    let service = Arbiter::registry().get::<Service>();
+    service.send(GetState(PhantomData)).map_err(|_| ())
+}
+
+/// Builds up the current arbiter's routing table before handing back a
+/// `Service` to send messages through. `Service::build(name)` starts the
+/// builder; each `add_*`/`wrap` call registers a route or layer, and
+/// `.address()` hands back the `Addr<Service>` callers send `SoarMessage`s
+/// to.
+pub struct ServiceBuilder {
+    name: String,
+}
+
+impl Service {
+    /// Start building a service named `name`. The name is for logging only
+    /// — every registration lands on the current arbiter's shared `Router`,
+    /// the same one every `Service` on this arbiter forwards to.
+    pub fn build(name: impl Into<String>) -> ServiceBuilder {
+        ServiceBuilder { name: name.into() }
+    }
+}
+
+impl ServiceBuilder {
+    /// Register `handler` as the route for `M`.
+    pub fn add_handler<M, H>(self, handler: H) -> Self
+        where M: SoarMessage,
+              H: RequestHandler<M>,
+    {
+        let service = Arbiter::registry().get::<Service>();
+        add_route::<M, _>(RemoteActor::new(handler, service).start());
+        self
+    }
+
+    /// Register a plain `HttpHandler<M>` (bincode-framed, no pooling or
+    /// retry) at `url`. Use `add_handler` with `HttpHandler::with_codec`,
+    /// or `add_http_handler_with_options` below, for a different codec or a
+    /// pooled, resilient client.
+    pub fn add_http_handler<M: SoarMessage>(self, url: Url) -> Self {
+        self.add_handler(HttpHandler::<M>::from(url))
+    }
+
+    /// Register an `HttpHandler<M>` backed by a pooled, resilient client
+    /// (connection reuse, retry-with-backoff, circuit-breaking) per `options`
+    /// — what makes a remote route production-safe. Bincode-framed; use
+    /// `add_handler` with `HttpHandler::with_options` directly for a
+    /// different codec.
+    pub fn add_http_handler_with_options<M: SoarMessage>(self, url: Url, options: HttpClientOptions) -> Self {
+        self.add_handler(HttpHandler::<M, Bincode>::with_options(url, Bincode::default(), options))
+    }
+
+    /// Register a layer to run on every message dispatched through this
+    /// arbiter's `Router`, outermost-first in registration order.
+    pub fn wrap(self, layer: impl Layer) -> Self {
+        crate::router::wrap_layer(layer);
+        self
+    }
+
+    /// Register `state` as this `Service`'s shared state for `S`, read by
+    /// every `add_fn_handler` call below (for this or any other `M`) that
+    /// asks for `State<S>`. Must be called before those `add_fn_handler`
+    /// calls; overwrites any state already registered for `S`.
+    pub fn with_state<S: 'static + Send + Sync>(self, state: S) -> Self {
+        set_state(state);
+        self
+    }
+
+    /// Register `f` as the handler for `M`, without hand-writing an actor.
+    /// `f` receives the `State<S>` registered via `with_state`; panics (at
+    /// the point the route resolves, not here) if none was registered for
+    /// `S`. See [`crate::extract`] for the free-function version that takes
+    /// an explicit `State<S>` instead of sharing one across a `Service`.
+    pub fn add_fn_handler<M, S, F>(self, f: F) -> Self
+        where M: SoarMessage,
+              S: 'static + Send + Sync,
+              F: 'static + Send + Fn(M, State<S>) -> Result<M::Response, Error>,
+    {
+        let state_fut = get_state::<S>().map(|state| {
+            state.expect("add_fn_handler: no state registered for this type; call with_state first")
+        });
+        crate::extract::add_fn_handler_from_service_state::<M, S, F>(f, state_fut);
+        self
+    }
+
+    /// Finish building, returning the `Addr<Service>` to send messages to.
+    pub fn address(self) -> Addr<Service> {
+        trace!("Service {:?} built", self.name);
+        Arbiter::registry().get::<Service>()
+    }
+}
+
+/// An actix-web resource handler serving JSON-RPC 2.0 over HTTP: parses the
+/// body as a single request or a batch (see [`JsonRpcBody`]), dispatches
+/// each by method name through the current arbiter's `Router`, and replies
+/// with the matching response object (or array, for a batch), omitting
+/// notifications per the spec. Register with
+/// `app.resource("/jsonrpc", |r| r.f(jsonrpc_handler))`.
+pub fn jsonrpc_handler(req: &HttpRequest) -> Box<dyn Future<Item=HttpResponse, Error=ActixError>> {
+    let fut = req.clone().json::<JsonRpcBody>()
+        .map_err(error::ErrorBadRequest)
+        .and_then(|body| handle_jsonrpc(body).map_err(error::ErrorInternalServerError))
+        .map(|resp| match resp {
+            Some(value) => HttpResponse::Ok().json(value),
+            None => HttpResponse::Ok().finish(),
+        });
+    Box::new(fut)
+}
+
+/// An actix-web resource handler serving the codec-negotiated (non-JSON-RPC)
+/// HTTP path: dispatches by method name (the `{method}` path segment) through
+/// the current arbiter's `Router`, decoding the body and encoding the reply
+/// per the request's `Content-Type` (see [`crate::http::codec`]). Register
+/// with `app.resource("/rpc/{method}", |r| r.f(rpc_handler))`.
+pub fn rpc_handler(req: &HttpRequest) -> Box<dyn Future<Item=HttpResponse, Error=ActixError>> {
+    let method = req.match_info().get("method").unwrap_or("").to_string();
+    let content_type = req.headers().get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+
+    let fut = req.clone().body()
+        .map_err(error::ErrorBadRequest)
+        .and_then(move |body| {
+            crate::router::send(DispatchBytes { method, content_type, body: body.to_vec() })
+                .map_err(error::ErrorInternalServerError)
+                .and_then(|res| future::result(res).map_err(error::ErrorInternalServerError))
+        })
+        .map(|(body, content_type)| HttpResponse::Ok().content_type(content_type).body(body));
+    Box::new(fut)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::client::ClientRequest;
+    use actix_web::test::TestServer;
+
+    use super::*;
+    use crate::get_type;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn test_jsonrpc_handler_handles_batch_with_notification() {
+        init_logger();
+        let mut server = TestServer::new(|app| {
+            app.resource("/jsonrpc", |r| r.f(jsonrpc_handler));
+        });
+        let url = Url::parse(&server.url("/jsonrpc")).unwrap();
+        let method = get_type!(TestMessage).to_string();
+
+        let res = server.execute(futures::future::lazy(move || {
+            add_route::<TestMessage, _>(TestHandler::default().start());
+
+            // A request with an `id` alongside a notification (no `id`).
+            let batch = serde_json::json!([
+                {"jsonrpc": "2.0", "method": method, "params": 9, "id": 1},
+                {"jsonrpc": "2.0", "method": method, "params": 3},
+            ]);
+            ClientRequest::post(url)
+                .json(batch)
+                .unwrap()
+                .send()
+                .map_err(Error::from)
+                .and_then(|resp| resp.json::<serde_json::Value>().map_err(Error::from))
+        })).unwrap();
+
+        // Only the request with an `id` gets a response back; the
+        // notification is dropped from the reply per the JSON-RPC spec.
+        let responses = res.as_array().expect("batch reply should be an array");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["result"], serde_json::json!(9));
+        assert_eq!(responses[0]["id"], serde_json::json!(1));
+    }
+
+    #[test]
+    fn test_rpc_handler_round_trips_json_codec() {
+        init_logger();
+        let mut server = TestServer::new(|app| {
+            app.resource("/rpc/{method}", |r| r.f(rpc_handler));
+        });
+        let method = get_type!(TestMessage).to_string();
+        let url = Url::parse(&server.url(&format!("/rpc/{}", method))).unwrap();
+
+        let res = server.execute(futures::future::lazy(move || {
+            add_route::<TestMessage, _>(TestHandler::default().start());
+
+            ClientRequest::post(url)
+                .header("Content-Type", "application/json")
+                .json(TestMessage(42))
+                .unwrap()
+                .send()
+                .map_err(Error::from)
+                .and_then(|resp| {
+                    let content_type = resp.headers().get("content-type")
+                        .and_then(|v| v.to_str().ok())
+                        .map(String::from);
+                    resp.json::<TestResponse>()
+                        .map_err(Error::from)
+                        .map(move |body| (content_type, body))
+                })
+        })).unwrap();
+
+        let (content_type, body) = res;
+        assert_eq!(content_type.as_deref(), Some("application/json"));
+        assert_eq!(body.0, 42);
+    }
+
+    #[test]
+    fn test_add_http_handler_with_options_uses_resilient_client() {
+        init_logger();
+        let mut server = TestServer::new(|app| {
+            app.resource("/test", |r| r.f(|_| {
+                let msg = bincode::serialize(&TestResponse(138)).unwrap();
+                actix_web::HttpResponse::Ok().body(msg)
+            }));
+        });
+
+        let url = Url::parse(&server.url("/test")).unwrap();
+        let res = server.execute(futures::future::lazy(|| {
+            let addr = Service::build("http_channel_test_builder_pooled")
+                .add_http_handler_with_options::<TestMessage>(url.clone(), HttpClientOptions::default())
+                .address();
+            addr.send(TestMessage(138))
+        })).unwrap();
+        assert_eq!(res.0, 138);
+    }
+
+    #[test]
+    fn test_add_fn_handler_shares_state_registered_with_with_state() {
+        init_logger();
+        let server = TestServer::new(|_| {});
+        let res = server.execute(futures::future::lazy(|| {
+            let addr = Service::build("fn_handler_state_test")
+                .with_state(10u8)
+                .add_fn_handler::<TestMessage, u8, _>(|msg, state: State<u8>| Ok(TestResponse(msg.0 + *state)))
+                .address();
+            addr.send(TestMessage(7))
+        })).unwrap();
+        assert_eq!(res.0, 17);
+    }
+}